@@ -1,63 +1,238 @@
 use std::{collections::HashMap, io::Write};
 
-use itertools::Itertools;
+use rayon::prelude::*;
+use ustr::Ustr;
 
-fn associative_hash(s: &[u8]) -> u64 {
-    s.iter()
-        .copied()
-        .map(u64::from)
-        .fold(0_u64, u64::wrapping_add)
+/// Canonical anagram key: a word's `char`s sorted into a `String`.
+fn canonical_key(s: &str) -> String {
+    let mut chars: Vec<char> = s.chars().collect();
+    chars.sort_unstable();
+    chars.into_iter().collect()
+}
+
+/// Uppercases a word's first `char` the Unicode-aware way (handles `ü`/`ö`/`ä`).
+fn uppercase_first_char(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
 }
 
 fn find_word_candidates(
-    dictionary_map: &HashMap<u64, Vec<String>>,
+    dictionary_map: &HashMap<String, Vec<Ustr>>,
     search_word: &str,
     first_char: Option<char>,
-) -> Vec<String> {
+) -> Vec<Ustr> {
     dictionary_map
-        .get(&associative_hash(search_word.as_bytes()))
+        .get(&canonical_key(search_word))
         .map_or_else(Vec::new, |words| {
             words
                 .iter()
                 .filter(|word| {
-                    first_char.map_or(true, |first_char| {
-                        word.chars()
-                            .next()
-                            .map_or(true, |first| first.to_ascii_uppercase() == first_char)
+                    first_char.is_none_or(|first_char| {
+                        word.chars().next().is_none_or(|first| {
+                            first.to_uppercase().eq(first_char.to_uppercase())
+                        })
                     })
                 })
+                .copied()
+                .collect()
+        })
+}
+
+/// Transliterates German umlauts and `ß` to their ASCII digraphs.
+fn transliterate_german(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| {
+            match c {
+                'ä' => "ae",
+                'ö' => "oe",
+                'ü' => "ue",
+                'ß' => "ss",
+                _ => return vec![c].into_iter(),
+            }
+            .chars()
+            .collect::<Vec<_>>()
+            .into_iter()
+        })
+        .collect()
+}
+
+/// Accent-insensitive dictionary index, keyed by the canonical key of each
+/// word's lowercased, transliterated form.
+static DICTIONARY_FOLDED: once_cell::sync::Lazy<HashMap<String, Vec<Ustr>>> =
+    once_cell::sync::Lazy::new(|| {
+        let dictionary = include_str!("german.dic");
+
+        let mut dictionary_map = HashMap::<String, Vec<Ustr>>::new();
+
+        dictionary.lines().for_each(|word| {
+            dictionary_map
+                .entry(canonical_key(&transliterate_german(&word.to_lowercase())))
+                .or_default()
+                .push(Ustr::from(word));
+        });
+
+        dictionary_map
+    });
+
+fn find_word_candidates_folded(search_word: &str, first_char: Option<char>) -> Vec<Ustr> {
+    DICTIONARY_FOLDED
+        .get(&canonical_key(&transliterate_german(
+            &search_word.to_lowercase(),
+        )))
+        .map_or_else(Vec::new, |words| {
+            words
+                .iter()
                 .filter(|word| {
-                    search_word
-                        .chars()
-                        .skip(usize::from(first_char.is_some()))
-                        .unique()
-                        .all(|c| {
-                            search_word
-                                .chars()
-                                .filter(|search_word_char| *search_word_char == c)
-                                .count()
-                                == word.chars().filter(|word_char| *word_char == c).count()
+                    first_char.is_none_or(|first_char| {
+                        word.chars().next().is_none_or(|first| {
+                            first.to_uppercase().eq(first_char.to_uppercase())
                         })
+                    })
                 })
-                .map(String::clone)
+                .copied()
                 .collect()
         })
 }
 
+/// Maximum number of dictionary words a single token may be decomposed into.
+const MAX_DECOMPOSITION_WORDS: usize = 3;
+
+/// Maximum number of decompositions to collect for a single token.
+const MAX_DECOMPOSITIONS: usize = 20;
+
+fn letter_counts(s: &str) -> HashMap<char, usize> {
+    let mut counts = HashMap::new();
+    for c in s.chars() {
+        *counts.entry(c).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Letter counts for every dictionary word, keyed case-insensitively.
+static DICTIONARY_WORD_COUNTS: once_cell::sync::Lazy<Vec<(Ustr, HashMap<char, usize>)>> =
+    once_cell::sync::Lazy::new(|| {
+        DICTIONARY
+            .values()
+            .flatten()
+            .copied()
+            .map(|word| (word, letter_counts(&word.to_lowercase())))
+            .collect()
+    });
+
+/// Index from a letter to the [`DICTIONARY_WORD_COUNTS`] entries containing it.
+static DICTIONARY_WORD_COUNTS_BY_LETTER: once_cell::sync::Lazy<HashMap<char, Vec<usize>>> =
+    once_cell::sync::Lazy::new(|| build_word_counts_index(&DICTIONARY_WORD_COUNTS));
+
+fn build_word_counts_index(
+    word_counts_table: &[(Ustr, HashMap<char, usize>)],
+) -> HashMap<char, Vec<usize>> {
+    let mut index = HashMap::<char, Vec<usize>>::new();
+    for (table_index, (_, word_counts)) in word_counts_table.iter().enumerate() {
+        for &c in word_counts.keys() {
+            index.entry(c).or_default().push(table_index);
+        }
+    }
+    index
+}
+
+fn decompose_word(
+    remaining_target: &HashMap<char, usize>,
+    word_counts_table: &[(Ustr, HashMap<char, usize>)],
+    word_counts_index: &HashMap<char, Vec<usize>>,
+    max_words: usize,
+    sequences: &mut Vec<Vec<Ustr>>,
+    current: &mut Vec<Ustr>,
+) {
+    if sequences.len() >= MAX_DECOMPOSITIONS {
+        return;
+    }
+
+    if remaining_target.values().all(|&count| count == 0) {
+        if current.len() > 1 {
+            sequences.push(current.clone());
+        }
+        return;
+    }
+
+    if max_words == 0 {
+        return;
+    }
+
+    let Some((&rarest_char, _)) = remaining_target
+        .iter()
+        .filter(|(_, &count)| count > 0)
+        .min_by_key(|(_, &count)| count)
+    else {
+        return;
+    };
+
+    let Some(candidate_indices) = word_counts_index.get(&rarest_char) else {
+        return;
+    };
+
+    for &candidate_index in candidate_indices {
+        let (word, word_counts) = &word_counts_table[candidate_index];
+
+        if word_counts
+            .iter()
+            .all(|(c, &count)| remaining_target.get(c).copied().unwrap_or(0) >= count)
+        {
+            let mut next_remaining = remaining_target.clone();
+            for (c, &count) in word_counts {
+                *next_remaining.get_mut(c).unwrap() -= count;
+            }
+
+            current.push(*word);
+            decompose_word(
+                &next_remaining,
+                word_counts_table,
+                word_counts_index,
+                max_words - 1,
+                sequences,
+                current,
+            );
+            current.pop();
+
+            if sequences.len() >= MAX_DECOMPOSITIONS {
+                return;
+            }
+        }
+    }
+}
+
+/// Tries to express `search_word`'s letters as a concatenation of two or
+/// more dictionary words (common with German compounds), matched
+/// case-insensitively; restoring capitalization is the caller's job.
+fn find_word_decompositions(search_word: &str) -> Vec<Vec<Ustr>> {
+    let mut sequences = Vec::new();
+    decompose_word(
+        &letter_counts(&search_word.to_lowercase()),
+        &DICTIONARY_WORD_COUNTS,
+        &DICTIONARY_WORD_COUNTS_BY_LETTER,
+        MAX_DECOMPOSITION_WORDS,
+        &mut sequences,
+        &mut Vec::new(),
+    );
+    sequences
+}
+
 const SPECIAL_CHARS_PRE: [char; 2] = ['(', '„'];
 const SPECIAL_CHARS_POST: [char; 7] = [',', '.', ')', '“', ':', '-', '?'];
 
-static DICTIONARY: once_cell::sync::Lazy<HashMap<u64, Vec<String>>> =
+static DICTIONARY: once_cell::sync::Lazy<HashMap<String, Vec<Ustr>>> =
     once_cell::sync::Lazy::new(|| {
         let dictionary = include_str!("german.dic");
 
-        let mut dictionary_map = HashMap::<u64, Vec<String>>::new();
+        let mut dictionary_map = HashMap::<String, Vec<Ustr>>::new();
 
         dictionary.lines().for_each(|word| {
             dictionary_map
-                .entry(associative_hash(word.as_bytes()))
+                .entry(canonical_key(word))
                 .or_default()
-                .push(word.to_string());
+                .push(Ustr::from(word));
         });
 
         dictionary_map
@@ -71,38 +246,204 @@ static SPECIAL_CHARS: once_cell::sync::Lazy<Vec<char>> = once_cell::sync::Lazy::
         .collect()
 });
 
+/// Optional unigram corpus-frequency table; empty if `german.freq` is absent.
+static WORD_FREQUENCIES: once_cell::sync::Lazy<HashMap<String, u64>> =
+    once_cell::sync::Lazy::new(|| {
+        std::fs::read_to_string("german.freq")
+            .ok()
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| {
+                        let (word, count) = line.rsplit_once(' ')?;
+                        Some((word.to_string(), count.parse().ok()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+
+/// Optional bigram corpus-frequency table; empty if `german.bigram.freq` is absent.
+static BIGRAM_FREQUENCIES: once_cell::sync::Lazy<HashMap<(String, String), u64>> =
+    once_cell::sync::Lazy::new(|| {
+        std::fs::read_to_string("german.bigram.freq")
+            .ok()
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| {
+                        let (pair, count) = line.rsplit_once(' ')?;
+                        let (first, second) = pair.split_once(' ')?;
+                        Some(((first.to_string(), second.to_string()), count.parse().ok()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+
+/// Total of all unigram counts, precomputed once instead of rescanning
+/// [`WORD_FREQUENCIES`] on every [`unigram_log_probability`] call.
+static WORD_FREQUENCY_TOTAL: once_cell::sync::Lazy<u64> =
+    once_cell::sync::Lazy::new(|| WORD_FREQUENCIES.values().sum());
+
+fn unigram_log_probability(word: &str) -> f64 {
+    let count = WORD_FREQUENCIES.get(word).copied().unwrap_or(1);
+    (count as f64 / (*WORD_FREQUENCY_TOTAL).max(1) as f64).ln()
+}
+
+fn bigram_log_probability(previous_word: &str, word: &str) -> f64 {
+    BIGRAM_FREQUENCIES
+        .get(&(previous_word.to_string(), word.to_string()))
+        .map(|&count| {
+            let previous_count = WORD_FREQUENCIES.get(previous_word).copied().unwrap_or(1);
+            (count as f64 / previous_count as f64).ln()
+        })
+        .unwrap_or(0.0)
+}
+
+/// Picks the most likely word at each line position via Viterbi decoding
+/// over the lattice of per-token anagram candidates. Returns, for each
+/// position, the index into that position's candidate list the decoded
+/// best path picks, or `None` if that position has no candidates.
+fn viterbi_decode(
+    candidate_lists: &[Vec<Ustr>],
+    unigram_log_probability: impl Fn(&str) -> f64,
+    bigram_log_probability: impl Fn(&str, &str) -> f64,
+) -> Vec<Option<usize>> {
+    let mut scores: Vec<Vec<f64>> = Vec::with_capacity(candidate_lists.len());
+    let mut backpointers: Vec<Vec<Option<usize>>> = Vec::with_capacity(candidate_lists.len());
+
+    for (position, words) in candidate_lists.iter().enumerate() {
+        let previous = position
+            .checked_sub(1)
+            .filter(|&previous_position| !candidate_lists[previous_position].is_empty())
+            .map(|previous_position| (&candidate_lists[previous_position], &scores[previous_position]));
+
+        let (position_scores, position_backpointers): (Vec<f64>, Vec<Option<usize>>) = words
+            .iter()
+            .map(|word| {
+                let unigram = unigram_log_probability(word);
+                previous.map_or((unigram, None), |(previous_words, previous_scores)| {
+                    let (best_previous, best_score) = previous_words
+                        .iter()
+                        .zip(previous_scores)
+                        .enumerate()
+                        .map(|(index, (previous_word, &previous_score))| {
+                            (index, previous_score + bigram_log_probability(previous_word, word))
+                        })
+                        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                        .unwrap();
+                    (best_score + unigram, Some(best_previous))
+                })
+            })
+            .unzip();
+
+        scores.push(position_scores);
+        backpointers.push(position_backpointers);
+    }
+
+    let mut chosen = vec![None; candidate_lists.len()];
+    let mut carry: Option<usize> = None;
+    for position in (0..candidate_lists.len()).rev() {
+        let best_at_position = carry.or_else(|| {
+            scores[position]
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(index, _)| index)
+        });
+
+        chosen[position] = best_at_position;
+        carry = best_at_position.and_then(|index| backpointers[position][index]);
+    }
+
+    chosen
+}
+
+fn find_word_candidates_for_token(clean_scrambled_word: &str) -> Vec<Ustr> {
+    let exact_candidates = find_word_candidates(&DICTIONARY, clean_scrambled_word, None);
+
+    let mut word_candidates: Vec<Ustr> = if !exact_candidates.is_empty() {
+        exact_candidates
+    } else {
+        find_word_candidates(
+            &DICTIONARY,
+            &clean_scrambled_word.to_lowercase(),
+            clean_scrambled_word.chars().find(|c| c.is_uppercase()),
+        )
+        .iter()
+        .map(|word| Ustr::from(uppercase_first_char(word).as_str()))
+        .collect()
+    };
+
+    if word_candidates.is_empty() {
+        word_candidates = find_word_candidates_folded(
+            clean_scrambled_word,
+            clean_scrambled_word.chars().find(|c| c.is_uppercase()),
+        );
+    }
+
+    if word_candidates.is_empty() {
+        let was_capitalized = clean_scrambled_word.chars().any(char::is_uppercase);
+
+        word_candidates = find_word_decompositions(clean_scrambled_word)
+            .into_iter()
+            .map(|words| {
+                let mut joined_words: Vec<String> = words.iter().map(ToString::to_string).collect();
+
+                if was_capitalized {
+                    if let Some(first_word) = joined_words.first_mut() {
+                        *first_word = uppercase_first_char(first_word);
+                    }
+                }
+
+                Ustr::from(joined_words.join(" ").as_str())
+            })
+            .collect();
+    }
+
+    word_candidates
+}
+
 fn unscramble_line(scrambled_line: &str, output: &mut impl Write) {
+    let clean_scrambled_words: Vec<String> = scrambled_line
+        .split_ascii_whitespace()
+        .map(|scrambled_word| {
+            scrambled_word
+                .chars()
+                .filter(|c| !SPECIAL_CHARS.contains(c))
+                .collect::<String>()
+        })
+        .collect();
+
+    let mut word_candidates_by_position: Vec<Vec<Ustr>> = clean_scrambled_words
+        .iter()
+        .map(|clean_scrambled_word| find_word_candidates_for_token(clean_scrambled_word))
+        .collect();
+
+    if !WORD_FREQUENCIES.is_empty() {
+        let chosen = viterbi_decode(
+            &word_candidates_by_position,
+            unigram_log_probability,
+            bigram_log_probability,
+        );
+
+        for (word_candidates, chosen_index) in word_candidates_by_position.iter_mut().zip(chosen) {
+            if let Some(chosen_index) = chosen_index {
+                *word_candidates = vec![word_candidates[chosen_index]];
+            }
+        }
+    }
+
     let mut first_word = true;
-    for scrambled_word in scrambled_line.split_ascii_whitespace() {
+    for (scrambled_word, (clean_scrambled_word, word_candidates)) in scrambled_line
+        .split_ascii_whitespace()
+        .zip(clean_scrambled_words.iter().zip(word_candidates_by_position.iter()))
+    {
         if !first_word {
             write!(output, " ").unwrap();
         }
 
-        let clean_scrambled_word = scrambled_word
-            .chars()
-            .filter(|c| !SPECIAL_CHARS.contains(c))
-            .collect::<String>();
-
-        let word_candidates = {
-            let mut word_candidates =
-                find_word_candidates(&DICTIONARY, &clean_scrambled_word, None);
-
-            if word_candidates.is_empty() {
-                word_candidates = find_word_candidates(
-                    &DICTIONARY,
-                    &clean_scrambled_word.to_lowercase(),
-                    clean_scrambled_word.chars().find(|c| c.is_uppercase()),
-                );
-
-                for word in &mut word_candidates {
-                    if let Some(first_char) = word.get_mut(..1) {
-                        first_char.make_ascii_uppercase();
-                    }
-                }
-            }
-            word_candidates
-        };
-
         scrambled_word
             .chars()
             .filter(|c| SPECIAL_CHARS_PRE.contains(c))
@@ -120,7 +461,14 @@ fn unscramble_line(scrambled_line: &str, output: &mut impl Write) {
                 write!(output, "{word_candidate}").unwrap();
             }
             word_candidates => {
-                write!(output, "{word_candidates:?}").unwrap();
+                write!(output, "[").unwrap();
+                for (index, word_candidate) in word_candidates.iter().enumerate() {
+                    if index > 0 {
+                        write!(output, ", ").unwrap();
+                    }
+                    write!(output, "{:?}", word_candidate.as_str()).unwrap();
+                }
+                write!(output, "]").unwrap();
             }
         };
 
@@ -136,13 +484,24 @@ fn unscramble_line(scrambled_line: &str, output: &mut impl Write) {
 }
 
 fn unscramble(scrambled_string: &str, output: &mut impl Write) {
+    let unscrambled_lines: Vec<Vec<u8>> = scrambled_string
+        .lines()
+        .collect::<Vec<_>>()
+        .par_iter()
+        .map(|scrambled_line| {
+            let mut line_output = Vec::new();
+            unscramble_line(scrambled_line, &mut line_output);
+            line_output
+        })
+        .collect();
+
     let mut first_line = true;
-    for scrambled_line in scrambled_string.lines() {
+    for line_output in unscrambled_lines {
         if !first_line {
             writeln!(output).unwrap();
         }
 
-        unscramble_line(scrambled_line, output);
+        output.write_all(&line_output).unwrap();
 
         first_line = false;
     }
@@ -157,12 +516,6 @@ fn main() {
             return;
         };
 
-    // Print debug stats about the used associative string hash
-    //dbg!(DICTIONARY.keys().count());
-    //dbg!(DICTIONARY.values().map(|words| words.len()).min());
-    //dbg!(DICTIONARY.values().map(|words| words.len()).max());
-    //dbg!(DICTIONARY.values().map(|words| words.len()).sum::<usize>() / DICTIONARY.values().count());
-
     let scrambled_string = std::fs::read_to_string(filepath).expect("Failed to read in file!");
     unscramble(&scrambled_string, &mut std::io::stdout());
 }
@@ -172,14 +525,15 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_associative_hash() {
-        const TEST_STRING: &[u8] = b"Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat. Duis aute irure dolor in reprehenderit in voluptate velit esse cillum dolore eu fugiat nulla pariatur. Excepteur sint occaecat cupidatat non proident, sunt in culpa qui officia deserunt mollit anim id est laborum.";
-        let mut sorted_string: Vec<_> = TEST_STRING.to_vec();
-        sorted_string.sort_unstable();
-        assert_eq!(
-            associative_hash(TEST_STRING),
-            associative_hash(&sorted_string)
-        );
+    fn test_canonical_key() {
+        const TEST_STRING: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat. Duis aute irure dolor in reprehenderit in voluptate velit esse cillum dolore eu fugiat nulla pariatur. Excepteur sint occaecat cupidatat non proident, sunt in culpa qui officia deserunt mollit anim id est laborum.";
+        let shuffled_string: String = {
+            let mut chars: Vec<char> = TEST_STRING.chars().rev().collect();
+            chars.sort_unstable();
+            chars.into_iter().collect()
+        };
+        assert_eq!(canonical_key(TEST_STRING), canonical_key(&shuffled_string));
+        assert_ne!(canonical_key("bd"), canonical_key("ae"));
     }
 
     #[test]
@@ -190,4 +544,83 @@ mod test {
         unscramble(TEST_STRING, &mut output_string);
         assert_eq!(String::from_utf8(output_string).unwrap(), EXPECTED_STRING);
     }
+
+    #[test]
+    fn test_unscramble_preserves_line_order() {
+        const TEST_STRING: &str = "rov\nhcan\nneben";
+        let mut output_string = Vec::new();
+        unscramble(TEST_STRING, &mut output_string);
+        assert_eq!(
+            String::from_utf8(output_string).unwrap(),
+            "`orv`\n`achn`\n`beenn`"
+        );
+    }
+
+    #[test]
+    fn test_find_word_candidates_folded_matches_missing_umlaut() {
+        assert_eq!(
+            find_word_candidates_folded("fuer", None),
+            vec![Ustr::from("für")]
+        );
+    }
+
+    #[test]
+    fn test_decompose_word_matches_capitalized_token() {
+        let word_counts_table = vec![
+            (Ustr::from("haus"), letter_counts("haus")),
+            (Ustr::from("tür"), letter_counts("tür")),
+        ];
+
+        let word_counts_index = build_word_counts_index(&word_counts_table);
+
+        let mut sequences = Vec::new();
+        decompose_word(
+            &letter_counts(&"atsuHrü".to_lowercase()),
+            &word_counts_table,
+            &word_counts_index,
+            MAX_DECOMPOSITION_WORDS,
+            &mut sequences,
+            &mut Vec::new(),
+        );
+
+        assert_eq!(sequences.len(), 1);
+        let mut found = sequences[0].clone();
+        found.sort_unstable();
+        assert_eq!(found, vec![Ustr::from("haus"), Ustr::from("tür")]);
+    }
+
+    #[test]
+    fn test_viterbi_decode_prefers_higher_frequency_candidate() {
+        let candidate_lists = vec![vec![Ustr::from("Sei"), Ustr::from("Sie")]];
+
+        let unigram = |word: &str| match word {
+            "Sie" => 1000.0_f64.ln(),
+            "Sei" => 1.0_f64.ln(),
+            _ => 0.0,
+        };
+        let bigram = |_: &str, _: &str| 0.0;
+
+        let chosen = viterbi_decode(&candidate_lists, unigram, bigram);
+
+        assert_eq!(chosen, vec![Some(1)]);
+    }
+
+    #[test]
+    fn test_find_word_candidates_returns_interned_words() {
+        let mut dictionary_map = HashMap::<String, Vec<Ustr>>::new();
+        let interned = Ustr::from("Haus");
+        dictionary_map
+            .entry(canonical_key("Haus"))
+            .or_default()
+            .push(interned);
+
+        let candidates = find_word_candidates(&dictionary_map, "Haus", None);
+
+        assert_eq!(candidates, vec![interned]);
+        assert_eq!(
+            candidates[0].as_char_ptr(),
+            interned.as_char_ptr(),
+            "candidate should be the same interned string, not a fresh allocation"
+        );
+    }
 }